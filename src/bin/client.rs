@@ -1,9 +1,15 @@
 use lazy_static::lazy_static;
-use socks_uot::UdpConfig;
+use socks_uot::{
+    crypto::{self, CryptoConfig, TunnelReader, TunnelWriter, CLIENT_TO_SERVER, SERVER_TO_CLIENT},
+    transport::{self, BoxedStream, TlsConfig, WsConfig},
+    UdpConfig,
+};
 use std::{
+    collections::HashMap,
     io::Cursor,
-    net::{Ipv4Addr, SocketAddr},
+    net::SocketAddr,
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use clap::Parser;
@@ -13,11 +19,9 @@ use socks5_proto::{
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, Error, ErrorKind, Result},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpListener, TcpStream, UdpSocket,
-    },
+    net::UdpSocket,
     sync::Mutex,
+    time,
 };
 
 #[derive(Parser)]
@@ -28,23 +32,37 @@ use tokio::{
     about = "A thin wrapper that supports UDP proxy over a TCP-only proxy system (client side)."
 )]
 struct Config {
-    /// The SOCKS5 inbound address.
+    /// The SOCKS5 inbound address. Accepts a `unix:/path` form to listen on
+    /// a Unix domain socket instead of TCP.
     #[clap(long, value_parser)]
     local: String,
     // /// The local IP returned by SOCKS5 UDP reply.
     // #[clap(long, value_parser)]
     // udp_local: String,
-    /// The address and port of the UoT server.
+    /// The address and port of the UoT server, as seen through `--remote`.
+    /// A `unix:/path` form dials the UoT server directly over a Unix domain
+    /// socket instead, bypassing `--remote` entirely.
     #[clap(long, value_parser)]
     server: String,
     /// The SOCKS5 inbound address of the downstream (TCP-only) proxy.
+    /// Accepts a `unix:/path` form to dial a Unix domain socket.
     #[clap(long, value_parser)]
     remote: String,
     #[clap(flatten)]
     udp: UdpConfig,
+    #[clap(flatten)]
+    crypto: CryptoConfig,
+    /// Idle timeout (in seconds) after which a per-flow UDP session entry is
+    /// evicted from the session table.
+    #[clap(long, value_parser, default_value = "30")]
+    session_ttl: u64,
+    #[clap(flatten)]
+    tls: TlsConfig,
+    #[clap(flatten)]
+    ws: WsConfig,
 }
 
-async fn handle_incoming(mut local: TcpStream, src_addr: SocketAddr) -> Result<()> {
+async fn handle_incoming(mut local: BoxedStream, src_addr: String) -> Result<()> {
     let hs_req = HandshakeRequest::read_from(&mut local).await?;
     if hs_req.methods.contains(&HandshakeMethod::None) {
         let hs_resp = HandshakeResponse::new(HandshakeMethod::None);
@@ -80,7 +98,7 @@ async fn handle_incoming(mut local: TcpStream, src_addr: SocketAddr) -> Result<(
         }
         Command::Associate => {
             log::info!("[{src_addr}] ASSOCIATE to {:?}", req.address);
-            let (remote, _addr) = connect_remote(string_to_address(&CONFIG.server)?).await?;
+            let mut remote = connect_server().await?;
 
             let (local_udp, local_addr) = socks_uot::create_udp_socket(
                 &CONFIG.local,
@@ -91,13 +109,23 @@ async fn handle_incoming(mut local: TcpStream, src_addr: SocketAddr) -> Result<(
 
             log::info!("[{src_addr}] local udp address {} {:?}", local_udp.local_addr()?, local_addr);
             let resp = Response::new(Reply::Succeeded, local_addr);
-            let (remote_read, remote_write) = remote.into_split();
-            let saddr = Arc::new(Mutex::new(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))));
+            let psk = CONFIG.crypto.psk.as_deref();
+            // A fresh salt per connection keeps two connections sharing the
+            // same `--psk` from deriving the same key and nonce sequence.
+            let salt = match psk {
+                Some(_) => crypto::exchange_salt(&mut remote, true).await?,
+                None => [0u8; crypto::SALT_LEN],
+            };
+            let (remote_read, remote_write) = transport::split(remote);
+            let server_write = TunnelWriter::new(remote_write, psk, CLIENT_TO_SERVER, &salt);
+            let server_read = TunnelReader::new(remote_read, psk, SERVER_TO_CLIENT, &salt);
+            let sessions = Arc::new(SessionTable::new(Duration::from_secs(CONFIG.session_ttl)));
             resp.write_to(&mut local).await?;
             if let Err(error) = tokio::select! {
                 result = hang_on_control_connection(local) => result,
-                result = uot_client_to_server(local_udp.clone(), remote_write, saddr.clone(), &src_addr) => result,
-                result = uot_server_to_client(local_udp, remote_read, saddr, &src_addr) => result,
+                result = uot_client_to_server(local_udp.clone(), server_write, sessions.clone(), &src_addr) => result,
+                result = uot_server_to_client(local_udp, server_read, sessions.clone(), &src_addr) => result,
+                _ = sessions.run_sweeper() => Ok(()),
             } {
                 if error.kind() != ErrorKind::UnexpectedEof {
                     log::error!("[{src_addr}] error when handling udp connection: {error:?}");
@@ -132,8 +160,32 @@ fn string_to_address(name: &str) -> Result<Address> {
     }
 }
 
-async fn connect_remote(address: Address) -> Result<(TcpStream, Address)> {
-    let mut stream = TcpStream::connect(&CONFIG.remote).await?;
+/// Connects to the UoT server at `--server`. A `unix:/path` address is local
+/// to this machine, so it's dialed directly, with `--tls`/`--ws` negotiated
+/// by [`transport::connect`] itself; any other address is only reachable by
+/// relaying a SOCKS5 CONNECT through `--remote`, so TLS and WebSocket have
+/// to be negotiated end-to-end with the UoT server on top of that relayed
+/// stream instead.
+async fn connect_server() -> Result<BoxedStream> {
+    if CONFIG.server.starts_with("unix:") {
+        return transport::connect(&CONFIG.server, Some(&CONFIG.tls), Some(&CONFIG.ws)).await;
+    }
+    let (remote, _addr) = connect_remote(string_to_address(&CONFIG.server)?).await?;
+    let host = CONFIG.server.rsplit_once(':').map_or(&CONFIG.server[..], |(h, _)| h);
+    let remote = if CONFIG.tls.tls {
+        transport::tls_connect(remote, host, &CONFIG.tls).await?
+    } else {
+        remote
+    };
+    if CONFIG.ws.ws {
+        transport::ws_connect(remote, host, &CONFIG.ws.ws_path).await
+    } else {
+        Ok(remote)
+    }
+}
+
+async fn connect_remote(address: Address) -> Result<(BoxedStream, Address)> {
+    let mut stream = transport::connect(&CONFIG.remote, None, None).await?;
     let hs_req = HandshakeRequest::new(vec![HandshakeMethod::None]);
     hs_req.write_to(&mut stream).await?;
     let hs_res = HandshakeResponse::read_from(&mut stream).await?;
@@ -155,19 +207,135 @@ async fn connect_remote(address: Address) -> Result<(TcpStream, Address)> {
     Ok((stream, resp.address))
 }
 
+struct Session {
+    local_addr: SocketAddr,
+    last_seen: Instant,
+}
+
+/// Maps the inner destination `Address` of each outbound datagram to the
+/// local SOCKS5 UDP source that most recently sent to it, so a single UDP
+/// association can demultiplex return traffic for several concurrent flows
+/// instead of funnelling everything back to whichever source sent last.
+struct SessionTable {
+    sessions: Mutex<HashMap<Address, Session>>,
+    ttl: Duration,
+}
+
+impl SessionTable {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    async fn record(&self, dest: Address, local_addr: SocketAddr) {
+        self.sessions.lock().await.insert(
+            dest,
+            Session {
+                local_addr,
+                last_seen: Instant::now(),
+            },
+        );
+    }
+
+    async fn lookup(&self, dest: &Address) -> Option<SocketAddr> {
+        let mut sessions = self.sessions.lock().await;
+        let session = sessions.get_mut(dest)?;
+        session.last_seen = Instant::now();
+        Some(session.local_addr)
+    }
+
+    async fn run_sweeper(&self) {
+        let mut interval = time::interval(self.ttl);
+        loop {
+            interval.tick().await;
+            self.sessions
+                .lock()
+                .await
+                .retain(|_, session| session.last_seen.elapsed() < self.ttl);
+        }
+    }
+}
+
+/// How long a partial fragment sequence may stay in progress, counted from
+/// its first fragment, before it's dropped.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// In-progress SOCKS5 UDP fragment sequence, keyed per `(src_addr, dest)` so
+/// that a slow client reassembling one flow doesn't interfere with another.
+struct Reassembly {
+    buf: Vec<u8>,
+    next_frag: u8,
+    started: Instant,
+}
+
+/// Buffers SOCKS5 UDP fragments (RFC 1928 section 7) until a terminating
+/// fragment (FRAG's high bit set) completes the sequence, per client source
+/// and destination. Fragments must arrive in strictly increasing order with
+/// no gaps; anything else discards the in-progress sequence, matching the
+/// "MAY" discard behavior the spec allows for out-of-order datagrams.
+#[derive(Default)]
+struct FragmentReassembler {
+    pending: HashMap<(SocketAddr, Address), Reassembly>,
+}
+
+impl FragmentReassembler {
+    /// Feeds one fragment in, returning the reassembled datagram once the
+    /// terminating fragment arrives. `cap` bounds the total reassembled size.
+    fn feed(&mut self, from_addr: SocketAddr, address: &Address, frag: u8, dgram: &[u8], cap: usize) -> Option<Vec<u8>> {
+        self.pending
+            .retain(|_, r| r.started.elapsed() < REASSEMBLY_TIMEOUT);
+
+        let key = (from_addr, address.clone());
+        let frag_num = frag & 0x7f;
+        let is_last = frag & 0x80 != 0;
+
+        if frag_num == 1 {
+            self.pending.insert(
+                key.clone(),
+                Reassembly {
+                    buf: Vec::new(),
+                    next_frag: 1,
+                    started: Instant::now(),
+                },
+            );
+        } else if self
+            .pending
+            .get(&key)
+            .is_none_or(|r| r.next_frag != frag_num)
+        {
+            // Gap, out-of-order, or no in-progress sequence: discard.
+            self.pending.remove(&key);
+            return None;
+        }
+
+        let reassembly = self.pending.get_mut(&key)?;
+        if reassembly.buf.len() + dgram.len() > cap {
+            self.pending.remove(&key);
+            return None;
+        }
+        reassembly.buf.extend_from_slice(dgram);
+        reassembly.next_frag = frag_num + 1;
+
+        if is_last {
+            self.pending.remove(&key).map(|r| r.buf)
+        } else {
+            None
+        }
+    }
+}
+
 async fn uot_client_to_server(
     socket: Arc<UdpSocket>,
-    mut server: OwnedWriteHalf,
-    src_udp_addr: Arc<Mutex<SocketAddr>>,
-    src_addr: &SocketAddr,
+    mut server: TunnelWriter<transport::BoxedWrite>,
+    sessions: Arc<SessionTable>,
+    src_addr: &str,
 ) -> Result<()> {
     let mut buf = vec![0u8; CONFIG.udp.mtu + 262];
+    let mut reassembler = FragmentReassembler::default();
     loop {
         let (len, from_addr) = socket.recv_from(&mut buf).await?;
-        {
-            let mut src_addr = src_udp_addr.lock().await;
-            *src_addr = from_addr;
-        }
         let mut cursor = Cursor::new(&buf[..len]);
         let header = UdpHeader::read_from(&mut cursor).await?;
         let dgram = &buf[cursor.position() as usize..len];
@@ -177,43 +345,103 @@ async fn uot_client_to_server(
             dgram.len(),
             header.frag,
         );
-        if header.frag != 0 {
-            continue;
-        }
+        let dgram = if header.frag == 0 {
+            dgram.to_vec()
+        } else {
+            match reassembler.feed(from_addr, &header.address, header.frag, dgram, CONFIG.udp.mtu) {
+                Some(complete) => complete,
+                None => continue,
+            }
+        };
+        sessions.record(header.address.clone(), from_addr).await;
         let mut data: Vec<u8> = vec![];
         header.address.write_to_buf(&mut data);
         data.extend_from_slice(&(dgram.len() as u16).to_be_bytes());
-        data.extend_from_slice(dgram);
-        server.write_all(&data).await?;
+        data.extend_from_slice(&dgram);
+        server.write_message(&data).await?;
+    }
+}
+
+/// SOCKS5's FRAG field is 1 byte with the high bit reserved to mark the last
+/// fragment, leaving fragment numbers 1..=127 — `(i + 1) as u8` must never be
+/// allowed to reach 128, or it wraps into that bit and corrupts the stream.
+const MAX_SOCKS5_FRAGMENTS: usize = 127;
+
+/// Splits `payload` into one or more SOCKS5 UDP datagrams addressed to
+/// `address`, none larger than `mtu`. Returns a single unfragmented datagram
+/// when `payload` already fits; otherwise splits across up to
+/// [`MAX_SOCKS5_FRAGMENTS`] fragments. Returns `None` if `payload` doesn't
+/// fit even at that fragment count, or if `mtu` is too small to fit even the
+/// header.
+fn fragment_datagram(address: &Address, payload: &[u8], mtu: usize) -> Option<Vec<Vec<u8>>> {
+    let mut header_bytes = vec![];
+    UdpHeader::new(0, address.clone()).write_to_buf(&mut header_bytes);
+    let max_payload = mtu.saturating_sub(header_bytes.len());
+    if max_payload == 0 {
+        return None;
+    }
+    if payload.len() <= max_payload {
+        let mut dgram = header_bytes;
+        dgram.extend_from_slice(payload);
+        return Some(vec![dgram]);
+    }
+    let chunks: Vec<_> = payload.chunks(max_payload).collect();
+    if chunks.len() > MAX_SOCKS5_FRAGMENTS {
+        return None;
     }
+    Some(
+        chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let frag_num = (i + 1) as u8;
+                let is_last = i + 1 == chunks.len();
+                let frag = if is_last { frag_num | 0x80 } else { frag_num };
+                let mut dgram = vec![];
+                UdpHeader::new(frag, address.clone()).write_to_buf(&mut dgram);
+                dgram.extend_from_slice(chunk);
+                dgram
+            })
+            .collect(),
+    )
 }
 
 async fn uot_server_to_client(
     socket: Arc<UdpSocket>,
-    mut server: OwnedReadHalf,
-    src_udp_addr: Arc<Mutex<SocketAddr>>,
-    src_addr: &SocketAddr,
+    mut server: TunnelReader<transport::BoxedRead>,
+    sessions: Arc<SessionTable>,
+    src_addr: &str,
 ) -> Result<()> {
     loop {
-        let address = Address::read_from(&mut server).await?;
-        let mut buf_len = [0; 2];
-        server.read_exact(&mut buf_len).await?;
-        let len = u16::from_be_bytes(buf_len);
-        let mut buf_dgram = vec![0; len as usize];
-        server.read_exact(&mut buf_dgram).await?;
-        log::debug!("[{src_addr}] UDP packet from {}, length {}", address, len);
-        let header = UdpHeader::new(0, address);
-        let mut final_dgram = vec![];
-        header.write_to_buf(&mut final_dgram);
-        final_dgram.extend_from_slice(&buf_dgram);
-        {
-            let src_addr = src_udp_addr.lock().await;
-            socket.send_to(&final_dgram, *src_addr).await?;
+        let (address, buf_dgram) = server.read_message().await?;
+        log::debug!(
+            "[{src_addr}] UDP packet from {}, length {}",
+            address,
+            buf_dgram.len()
+        );
+        let Some(local_addr) = sessions.lookup(&address).await else {
+            log::debug!("[{src_addr}] no known local session for {address}, dropping packet");
+            continue;
+        };
+
+        // The reassembled datagram may not fit the local MTU; split it back
+        // into SOCKS5 UDP fragments (RFC 1928 section 7) when it doesn't.
+        match fragment_datagram(&address, &buf_dgram, CONFIG.udp.mtu) {
+            Some(datagrams) => {
+                for dgram in datagrams {
+                    socket.send_to(&dgram, local_addr).await?;
+                }
+            }
+            None => log::warn!(
+                "[{src_addr}] packet to {address} ({} bytes) doesn't fit local mtu {} even fragmented, dropping",
+                buf_dgram.len(),
+                CONFIG.udp.mtu
+            ),
         }
     }
 }
 
-async fn hang_on_control_connection(mut stream: TcpStream) -> Result<()> {
+async fn hang_on_control_connection(mut stream: BoxedStream) -> Result<()> {
     let mut buf = [0; 1024];
     loop {
         match stream.read(&mut buf).await {
@@ -226,12 +454,12 @@ async fn hang_on_control_connection(mut stream: TcpStream) -> Result<()> {
 }
 
 async fn listen() -> Result<()> {
-    let listener = TcpListener::bind(&CONFIG.local).await?;
+    let listener = transport::Listener::bind(&CONFIG.local, None, None).await?;
     loop {
         let (stream, src_addr) = listener.accept().await?;
         tokio::spawn(async move {
             log::debug!("[{src_addr}] incoming connection accepted");
-            let result = handle_incoming(stream, src_addr).await;
+            let result = handle_incoming(stream, src_addr.clone()).await;
             match result {
                 Ok(()) => log::debug!("[{src_addr}] done handling, stream closed"),
                 Err(err) => log::warn!("[{src_addr}] error handling: {err}"),
@@ -249,3 +477,99 @@ async fn main() -> Result<()> {
     env_logger::init();
     listen().await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dest() -> Address {
+        Address::SocketAddress("127.0.0.1:1234".parse().unwrap())
+    }
+
+    fn src() -> SocketAddr {
+        "127.0.0.1:9999".parse().unwrap()
+    }
+
+    #[test]
+    fn reassembles_in_order_fragments() {
+        let mut r = FragmentReassembler::default();
+        assert_eq!(r.feed(src(), &dest(), 1, b"hel", 1024), None);
+        assert_eq!(r.feed(src(), &dest(), 2, b"lo ", 1024), None);
+        assert_eq!(
+            r.feed(src(), &dest(), 3 | 0x80, b"world", 1024),
+            Some(b"hello world".to_vec())
+        );
+    }
+
+    #[test]
+    fn discards_gapped_fragments() {
+        let mut r = FragmentReassembler::default();
+        assert_eq!(r.feed(src(), &dest(), 1, b"hel", 1024), None);
+        // Fragment 3 skips fragment 2: the in-progress sequence is dropped,
+        // and fragment 3 itself (not being a `frag_num == 1` start) is
+        // discarded too.
+        assert_eq!(r.feed(src(), &dest(), 3 | 0x80, b"world", 1024), None);
+        // A later, correctly-sequenced fragment 1 starts a fresh sequence.
+        assert_eq!(r.feed(src(), &dest(), 1 | 0x80, b"hi", 1024), Some(b"hi".to_vec()));
+    }
+
+    #[test]
+    fn discards_sequences_over_the_size_cap() {
+        let mut r = FragmentReassembler::default();
+        assert_eq!(r.feed(src(), &dest(), 1, b"0123456789", 15), None);
+        assert_eq!(r.feed(src(), &dest(), 2 | 0x80, b"0123456789", 15), None);
+    }
+
+    #[test]
+    fn fragment_datagram_fits_unfragmented() {
+        let datagrams = fragment_datagram(&dest(), b"hello", 1024).unwrap();
+        assert_eq!(datagrams.len(), 1);
+    }
+
+    #[test]
+    fn fragment_datagram_splits_oversized_payloads() {
+        let payload = vec![0u8; 1000];
+        let mut header_bytes = vec![];
+        UdpHeader::new(0, dest()).write_to_buf(&mut header_bytes);
+        let mtu = header_bytes.len() + 100;
+        let datagrams = fragment_datagram(&dest(), &payload, mtu).unwrap();
+        assert!(datagrams.len() > 1);
+        for dgram in &datagrams {
+            assert!(dgram.len() <= mtu);
+        }
+    }
+
+    #[test]
+    fn fragment_datagram_refuses_beyond_127_fragments() {
+        // 127 fragments worth of payload at this MTU is fine...
+        let mtu = 20;
+        let mut header_bytes = vec![];
+        UdpHeader::new(0, dest()).write_to_buf(&mut header_bytes);
+        let max_payload = mtu - header_bytes.len();
+        let fits = vec![0u8; max_payload * MAX_SOCKS5_FRAGMENTS];
+        assert!(fragment_datagram(&dest(), &fits, mtu).is_some());
+        // ...but one byte more needs a 128th fragment, which would wrap the
+        // FRAG byte into the "last fragment" bit if not rejected outright.
+        let too_big = vec![0u8; max_payload * MAX_SOCKS5_FRAGMENTS + 1];
+        assert!(fragment_datagram(&dest(), &too_big, mtu).is_none());
+    }
+
+    #[tokio::test]
+    async fn session_table_looks_up_recorded_sessions() {
+        let table = SessionTable::new(Duration::from_secs(30));
+        table.record(dest(), src()).await;
+        assert_eq!(table.lookup(&dest()).await, Some(src()));
+    }
+
+    #[tokio::test]
+    async fn session_table_evicts_entries_past_their_ttl() {
+        let ttl = Duration::from_millis(20);
+        let table = SessionTable::new(ttl);
+        table.record(dest(), src()).await;
+        tokio::select! {
+            _ = table.run_sweeper() => {}
+            _ = time::sleep(ttl * 5) => {}
+        }
+        assert_eq!(table.lookup(&dest()).await, None);
+    }
+}
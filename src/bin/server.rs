@@ -1,16 +1,23 @@
 use lazy_static::lazy_static;
-use socks_uot::UdpConfig;
-use std::{net::SocketAddr, sync::Arc};
+use socks_uot::{
+    crypto::{self, CryptoConfig, TunnelReader, TunnelWriter, CLIENT_TO_SERVER, SERVER_TO_CLIENT},
+    transport::{self, TlsConfig, WsConfig},
+    UdpConfig,
+};
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use clap::Parser;
 use socks5_proto::Address;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, Error, ErrorKind, Result},
-    net::{
-        self,
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpListener, TcpStream, UdpSocket,
-    },
+    io::{Error, ErrorKind, Result},
+    net::{self, UdpSocket},
+    time,
 };
 
 #[derive(Parser)]
@@ -19,23 +26,87 @@ use tokio::{
 #[clap(version = "0.1.0")]
 #[clap(about = "A thin wrapper that supports UDP proxy over a TCP-only proxy system (server side).")]
 struct Config {
-    /// The listening address for client connections.
+    /// The listening address for client connections. Accepts a `unix:/path`
+    /// form to listen on a Unix domain socket instead of TCP.
     #[clap(long, value_parser)]
     local: String,
     #[clap(flatten)]
     udp: UdpConfig,
+    #[clap(flatten)]
+    crypto: CryptoConfig,
+    /// Idle timeout (in seconds) after which a UDP association with no
+    /// traffic in either direction is torn down and its port reclaimed.
+    #[clap(long, value_parser, default_value = "60")]
+    udp_timeout: u64,
+    #[clap(flatten)]
+    tls: TlsConfig,
+    #[clap(flatten)]
+    ws: WsConfig,
+}
+
+/// Number of seconds since process start, refreshed once per second by
+/// [`run_coarse_clock`] so per-packet activity tracking costs an atomic
+/// load instead of a `SystemTime`/`Instant` syscall.
+static COARSE_NOW: AtomicU64 = AtomicU64::new(0);
+static ACTIVE_ASSOCIATIONS: AtomicUsize = AtomicUsize::new(0);
+
+fn coarse_now() -> u64 {
+    COARSE_NOW.load(Ordering::Relaxed)
+}
+
+async fn run_coarse_clock() {
+    let start = Instant::now();
+    let mut interval = time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        COARSE_NOW.store(start.elapsed().as_secs(), Ordering::Relaxed);
+    }
+}
+
+/// Returns once the association has seen no traffic for `timeout_secs`
+/// seconds, so the enclosing `select!` can tear down the UDP socket and let
+/// the OS reclaim its port.
+async fn idle_monitor(last_activity: Arc<AtomicU64>, timeout_secs: u64) {
+    let mut interval = time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        if coarse_now().saturating_sub(last_activity.load(Ordering::Relaxed)) >= timeout_secs {
+            return;
+        }
+    }
 }
 
-async fn handle_incoming(stream: TcpStream, client_addr: SocketAddr) -> Result<()> {
+async fn handle_incoming(mut stream: transport::BoxedStream, client_addr: String) -> Result<()> {
     let (udp, _) =
         socks_uot::create_udp_socket(&CONFIG.local, CONFIG.udp.min_port, CONFIG.udp.max_port)
             .await?;
-    let (client_read, client_write) = stream.into_split();
+    let psk = CONFIG.crypto.psk.as_deref();
+    // Must agree with the client's `exchange_salt(.., true)` before the
+    // stream is split, so both directions' keys are derived from the same
+    // per-connection salt.
+    let salt = match psk {
+        Some(_) => crypto::exchange_salt(&mut stream, false).await?,
+        None => [0u8; crypto::SALT_LEN],
+    };
+    let (client_read, client_write) = transport::split(stream);
+    let client_read = TunnelReader::new(client_read, psk, CLIENT_TO_SERVER, &salt);
+    let client_write = TunnelWriter::new(client_write, psk, SERVER_TO_CLIENT, &salt);
+    let last_activity = Arc::new(AtomicU64::new(coarse_now()));
+
+    let active = ACTIVE_ASSOCIATIONS.fetch_add(1, Ordering::Relaxed) + 1;
+    log::info!("[{client_addr}] UDP association opened, {active} active");
+    let result = tokio::select! {
+        result = uot_client_to_server(udp.clone(), client_read, last_activity.clone(), &client_addr) => result,
+        result = uot_server_to_client(udp, client_write, last_activity.clone(), &client_addr) => result,
+        _ = idle_monitor(last_activity, CONFIG.udp_timeout) => {
+            log::info!("[{client_addr}] UDP association idle for {}s, closing", CONFIG.udp_timeout);
+            Ok(())
+        }
+    };
+    let active = ACTIVE_ASSOCIATIONS.fetch_sub(1, Ordering::Relaxed) - 1;
+    log::info!("[{client_addr}] UDP association closed, {active} active");
 
-    if let Err(error) = tokio::select! {
-        result = uot_client_to_server(udp.clone(), client_read, &client_addr) => result,
-        result = uot_server_to_client(udp, client_write, &client_addr) => result,
-    } {
+    if let Err(error) = result {
         if error.kind() != ErrorKind::UnexpectedEof {
             log::error!("[{client_addr}] error when handling udp connection: {error:?}");
         }
@@ -45,18 +116,17 @@ async fn handle_incoming(stream: TcpStream, client_addr: SocketAddr) -> Result<(
 
 async fn uot_client_to_server(
     udp: Arc<UdpSocket>,
-    mut client: OwnedReadHalf,
-    client_addr: &SocketAddr,
+    mut client: TunnelReader<transport::BoxedRead>,
+    last_activity: Arc<AtomicU64>,
+    client_addr: &str,
 ) -> Result<()> {
     loop {
-        let address = Address::read_from(&mut client).await?;
-        let mut buf_len = [0; 2];
-        client.read_exact(&mut buf_len).await?;
-        let len = u16::from_be_bytes(buf_len);
-        let mut buf_dgram = vec![0; len as usize];
-        client.read_exact(&mut buf_dgram).await?;
-
-        log::debug!("[{client_addr}] UDP packet to {address}, length {len}");
+        let (address, buf_dgram) = client.read_message().await?;
+        last_activity.store(coarse_now(), Ordering::Relaxed);
+        log::debug!(
+            "[{client_addr}] UDP packet to {address}, length {}",
+            buf_dgram.len()
+        );
         let address = match address {
             Address::SocketAddress(address) => address,
             Address::DomainAddress(domain, port) => {
@@ -78,28 +148,30 @@ async fn uot_client_to_server(
 
 async fn uot_server_to_client(
     udp: Arc<UdpSocket>,
-    mut client: OwnedWriteHalf,
-    client_addr: &SocketAddr,
+    mut client: TunnelWriter<transport::BoxedWrite>,
+    last_activity: Arc<AtomicU64>,
+    client_addr: &str,
 ) -> Result<()> {
     let mut buf = vec![0; CONFIG.udp.mtu];
     loop {
         let (len, from) = udp.recv_from(&mut buf).await?;
+        last_activity.store(coarse_now(), Ordering::Relaxed);
         let mut message = vec![];
         log::debug!("[{client_addr}] UDP packet from {from:?}, length {len}");
         Address::SocketAddress(from).write_to_buf(&mut message);
         message.extend_from_slice(&(len as u16).to_be_bytes());
         message.extend_from_slice(&buf[..len]);
-        client.write_all(&message).await?;
+        client.write_message(&message).await?;
     }
 }
 
 async fn listen() -> Result<()> {
-    let listener = TcpListener::bind(&CONFIG.local).await?;
+    let listener = transport::Listener::bind(&CONFIG.local, Some(&CONFIG.tls), Some(&CONFIG.ws)).await?;
     loop {
         let (stream, client_addr) = listener.accept().await?;
         tokio::spawn(async move {
             log::debug!("[{client_addr}] incoming connection accepted");
-            let result = handle_incoming(stream, client_addr).await;
+            let result = handle_incoming(stream, client_addr.clone()).await;
             match result {
                 Ok(()) => log::debug!("[{client_addr}] done handling, stream closed"),
                 Err(err) => log::warn!("[{client_addr}] error handling: {err}"),
@@ -115,6 +187,58 @@ lazy_static! {
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
+    tokio::spawn(run_coarse_clock());
     listen().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::Mutex as AsyncMutex;
+
+    // idle_monitor reads the process-global COARSE_NOW clock directly, so
+    // tests that drive it manually must not run concurrently with each other.
+    static CLOCK_LOCK: AsyncMutex<()> = AsyncMutex::const_new(());
+
+    async fn tick_coarse_clock(seconds: u64) {
+        for now in 1..=seconds {
+            time::advance(Duration::from_secs(1)).await;
+            COARSE_NOW.store(now, Ordering::Relaxed);
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_monitor_returns_once_traffic_goes_stale() {
+        let _guard = CLOCK_LOCK.lock().await;
+        COARSE_NOW.store(0, Ordering::Relaxed);
+        let last_activity = Arc::new(AtomicU64::new(0));
+        let handle = tokio::spawn(idle_monitor(last_activity, 3));
+
+        tick_coarse_clock(3).await;
+
+        time::timeout(Duration::from_secs(1), handle)
+            .await
+            .expect("idle_monitor should have returned once idle past the timeout")
+            .unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_monitor_keeps_running_while_traffic_is_recent() {
+        let _guard = CLOCK_LOCK.lock().await;
+        COARSE_NOW.store(0, Ordering::Relaxed);
+        let last_activity = Arc::new(AtomicU64::new(0));
+        let handle = tokio::spawn(idle_monitor(last_activity.clone(), 3));
+
+        for now in 1..=5 {
+            time::advance(Duration::from_secs(1)).await;
+            COARSE_NOW.store(now, Ordering::Relaxed);
+            last_activity.store(now, Ordering::Relaxed);
+            tokio::task::yield_now().await;
+        }
+
+        assert!(!handle.is_finished());
+        handle.abort();
+    }
+}
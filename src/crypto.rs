@@ -0,0 +1,257 @@
+use std::io::Cursor;
+
+use blake2::Blake2b512;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use clap::Parser;
+use hkdf::SimpleHkdf;
+use rand::Rng;
+use socks5_proto::Address;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Error, ErrorKind, Result};
+
+/// HKDF info label binding the client-to-server direction's derived key.
+pub const CLIENT_TO_SERVER: &[u8] = b"uot-client-to-server";
+/// HKDF info label binding the server-to-client direction's derived key.
+pub const SERVER_TO_CLIENT: &[u8] = b"uot-server-to-client";
+
+const NONCE_LEN: usize = 12;
+
+/// Length of the random per-connection salt mixed into both directions' HKDF
+/// info. Every connection gets its own salt, so two connections sharing the
+/// same `--psk` never derive the same key and therefore never start a
+/// `NonceCounter` from the same state under the same key.
+pub const SALT_LEN: usize = 16;
+
+#[derive(Parser, Clone)]
+pub struct CryptoConfig {
+    /// Pre-shared passphrase used to encrypt the tunnel with ChaCha20-Poly1305.
+    /// If unset, the tunnel is left in plaintext.
+    #[clap(long, value_parser)]
+    pub psk: Option<String>,
+}
+
+/// Generates and sends a random per-connection salt if `initiator`, otherwise
+/// reads back the salt the initiator sent. Must run, over the full duplex
+/// stream, before it's split into the halves handed to [`TunnelReader::new`]
+/// and [`TunnelWriter::new`] — both sides need the same salt to derive the
+/// same keys. Only needed when a PSK is configured; skip it otherwise.
+pub async fn exchange_salt<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    initiator: bool,
+) -> Result<[u8; SALT_LEN]> {
+    let mut salt = [0u8; SALT_LEN];
+    if initiator {
+        rand::thread_rng().fill(&mut salt);
+        stream.write_all(&salt).await?;
+        stream.flush().await?;
+    } else {
+        stream.read_exact(&mut salt).await?;
+    }
+    Ok(salt)
+}
+
+fn derive_key(psk: &str, label: &[u8], salt: &[u8]) -> Key {
+    // Blake2b's variable-output core uses lazy buffering, which the default
+    // `Hmac`-backed `Hkdf` can't drive; `SimpleHkdf` uses `SimpleHmac`, which
+    // works with any `Digest` impl.
+    let hk = SimpleHkdf::<Blake2b512>::new(None, psk.as_bytes());
+    let mut key = [0u8; 32];
+    let mut info = Vec::with_capacity(label.len() + salt.len());
+    info.extend_from_slice(label);
+    info.extend_from_slice(salt);
+    hk.expand(&info, &mut key)
+        .expect("32 bytes is a valid HKDF output length");
+    key.into()
+}
+
+/// A per-direction 96-bit nonce counter. Nonce reuse under a fixed key breaks
+/// ChaCha20-Poly1305's guarantees, so the connection is aborted instead of
+/// wrapping around.
+struct NonceCounter(u128);
+
+impl NonceCounter {
+    const MAX: u128 = (1 << (NONCE_LEN * 8)) - 1;
+
+    fn new() -> Self {
+        Self(0)
+    }
+
+    fn next(&mut self) -> Result<Nonce> {
+        if self.0 > Self::MAX {
+            return Err(Error::other(
+                "nonce counter exhausted, refusing to reuse a nonce",
+            ));
+        }
+        let bytes = self.0.to_le_bytes();
+        self.0 += 1;
+        Ok(*Nonce::from_slice(&bytes[..NONCE_LEN]))
+    }
+}
+
+/// Reads the `[Address][len][payload]` frame out of a (possibly decrypted)
+/// byte stream, mirroring the layout `uot_client_to_server`/
+/// `uot_server_to_client` write on the wire.
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(Address, Vec<u8>)> {
+    let address = Address::read_from(reader).await?;
+    let mut len_buf = [0u8; 2];
+    reader.read_exact(&mut len_buf).await?;
+    let len = u16::from_be_bytes(len_buf);
+    let mut dgram = vec![0u8; len as usize];
+    reader.read_exact(&mut dgram).await?;
+    Ok((address, dgram))
+}
+
+pub struct CryptoReader<R> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    nonce: NonceCounter,
+}
+
+impl<R: AsyncRead + Unpin> CryptoReader<R> {
+    fn new(inner: R, psk: &str, label: &[u8], salt: &[u8]) -> Self {
+        Self {
+            inner,
+            cipher: ChaCha20Poly1305::new(&derive_key(psk, label, salt)),
+            nonce: NonceCounter::new(),
+        }
+    }
+
+    async fn read_frame(&mut self) -> Result<Vec<u8>> {
+        let mut len_buf = [0u8; 2];
+        self.inner.read_exact(&mut len_buf).await?;
+        let len = u16::from_be_bytes(len_buf) as usize;
+        let mut ciphertext = vec![0u8; len];
+        self.inner.read_exact(&mut ciphertext).await?;
+        let nonce = self.nonce.next()?;
+        self.cipher
+            .decrypt(&nonce, ciphertext.as_slice())
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "AEAD authentication failed"))
+    }
+}
+
+pub struct CryptoWriter<W> {
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    nonce: NonceCounter,
+}
+
+impl<W: AsyncWrite + Unpin> CryptoWriter<W> {
+    fn new(inner: W, psk: &str, label: &[u8], salt: &[u8]) -> Self {
+        Self {
+            inner,
+            cipher: ChaCha20Poly1305::new(&derive_key(psk, label, salt)),
+            nonce: NonceCounter::new(),
+        }
+    }
+
+    async fn write_frame(&mut self, plaintext: &[u8]) -> Result<()> {
+        let nonce = self.nonce.next()?;
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| Error::other("AEAD encryption failed"))?;
+        self.inner
+            .write_all(&(ciphertext.len() as u16).to_be_bytes())
+            .await?;
+        self.inner.write_all(&ciphertext).await?;
+        self.inner.flush().await
+    }
+}
+
+/// The receiving half of a UoT control channel, transparently decrypting
+/// frames when a PSK is configured for this direction. Generic over the
+/// underlying transport's read half so it works the same way over TCP, TLS,
+/// or any other `AsyncRead` implementation.
+pub enum TunnelReader<R> {
+    Plain(R),
+    Encrypted(CryptoReader<R>),
+}
+
+impl<R: AsyncRead + Unpin> TunnelReader<R> {
+    /// `salt` must be the same per-connection salt obtained from
+    /// [`exchange_salt`] that the peer's [`TunnelWriter::new`] for this
+    /// direction used; ignored when `psk` is `None`.
+    pub fn new(inner: R, psk: Option<&str>, label: &[u8], salt: &[u8]) -> Self {
+        match psk {
+            Some(psk) => Self::Encrypted(CryptoReader::new(inner, psk, label, salt)),
+            None => Self::Plain(inner),
+        }
+    }
+
+    pub async fn read_message(&mut self) -> Result<(Address, Vec<u8>)> {
+        match self {
+            Self::Plain(inner) => read_message(inner).await,
+            Self::Encrypted(reader) => {
+                let frame = reader.read_frame().await?;
+                read_message(&mut Cursor::new(frame)).await
+            }
+        }
+    }
+}
+
+/// The sending half of a UoT control channel, transparently encrypting
+/// frames when a PSK is configured for this direction. Generic over the
+/// underlying transport's write half so it works the same way over TCP,
+/// TLS, or any other `AsyncWrite` implementation.
+pub enum TunnelWriter<W> {
+    Plain(W),
+    Encrypted(CryptoWriter<W>),
+}
+
+impl<W: AsyncWrite + Unpin> TunnelWriter<W> {
+    /// `salt` must be the same per-connection salt obtained from
+    /// [`exchange_salt`] that the peer's [`TunnelReader::new`] for this
+    /// direction used; ignored when `psk` is `None`.
+    pub fn new(inner: W, psk: Option<&str>, label: &[u8], salt: &[u8]) -> Self {
+        match psk {
+            Some(psk) => Self::Encrypted(CryptoWriter::new(inner, psk, label, salt)),
+            None => Self::Plain(inner),
+        }
+    }
+
+    pub async fn write_message(&mut self, data: &[u8]) -> Result<()> {
+        match self {
+            Self::Plain(inner) => {
+                inner.write_all(data).await?;
+                inner.flush().await
+            }
+            Self::Encrypted(writer) => writer.write_frame(data).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nonce_counter_increments() {
+        let mut counter = NonceCounter::new();
+        assert_eq!(counter.next().unwrap(), counter_at(0));
+        assert_eq!(counter.next().unwrap(), counter_at(1));
+        assert_eq!(counter.next().unwrap(), counter_at(2));
+    }
+
+    #[test]
+    fn nonce_counter_refuses_to_wrap() {
+        let mut counter = NonceCounter(NonceCounter::MAX);
+        assert_eq!(counter.next().unwrap(), counter_at(NonceCounter::MAX));
+        assert!(counter.next().is_err());
+        // Stays exhausted rather than wrapping back around to a reused nonce.
+        assert!(counter.next().is_err());
+    }
+
+    fn counter_at(value: u128) -> Nonce {
+        let bytes = value.to_le_bytes();
+        *Nonce::from_slice(&bytes[..NONCE_LEN])
+    }
+
+    #[test]
+    fn derive_key_varies_with_salt() {
+        let key_a = derive_key("hunter2", CLIENT_TO_SERVER, &[0u8; SALT_LEN]);
+        let key_b = derive_key("hunter2", CLIENT_TO_SERVER, &[1u8; SALT_LEN]);
+        assert_ne!(key_a, key_b);
+    }
+}
@@ -7,6 +7,9 @@ use tokio::net::{self, UdpSocket};
 
 use rand::Rng;
 
+pub mod crypto;
+pub mod transport;
+
 #[derive(Parser)]
 pub struct UdpConfig {
     /// The MTU of the UDP sockets.
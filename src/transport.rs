@@ -0,0 +1,303 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use clap::Parser;
+use futures_util::{Sink, Stream as FutureStream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, Error, ErrorKind, ReadHalf, Result, WriteHalf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+use tokio_rustls::{
+    rustls::{self, pki_types::ServerName},
+    TlsAcceptor, TlsConnector,
+};
+use tokio_tungstenite::tungstenite::Message;
+
+#[derive(Parser, Clone)]
+pub struct TlsConfig {
+    /// Wrap this endpoint's connection in TLS.
+    #[clap(long, value_parser)]
+    pub tls: bool,
+    /// PEM certificate chain. Required to accept TLS connections; used as an
+    /// additional trust anchor when dialing out over TLS.
+    #[clap(long, value_parser)]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key matching `--tls-cert`. Required to accept TLS
+    /// connections, unused when dialing out.
+    #[clap(long, value_parser)]
+    pub tls_key: Option<PathBuf>,
+}
+
+#[derive(Parser, Clone)]
+pub struct WsConfig {
+    /// Carry this endpoint's connection inside a WebSocket upgrade, so it
+    /// survives HTTP-only egress points, reverse proxies, and CDNs that
+    /// reject raw TCP.
+    #[clap(long, value_parser)]
+    pub ws: bool,
+    /// HTTP path used for the WebSocket upgrade request.
+    #[clap(long, value_parser, default_value = "/")]
+    pub ws_path: String,
+}
+
+/// A connected or accepted duplex byte stream, regardless of which concrete
+/// transport produced it.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+pub type BoxedStream = Box<dyn Stream>;
+pub type BoxedRead = ReadHalf<BoxedStream>;
+pub type BoxedWrite = WriteHalf<BoxedStream>;
+
+/// Splits a boxed duplex stream into independently ownable read/write
+/// halves, mirroring `TcpStream::into_split` for any transport.
+pub fn split(stream: BoxedStream) -> (BoxedRead, BoxedWrite) {
+    tokio::io::split(stream)
+}
+
+enum Endpoint {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    fn parse(addr: &str) -> Self {
+        match addr.strip_prefix("unix:") {
+            Some(path) => Self::Unix(PathBuf::from(path)),
+            None => Self::Tcp(addr.to_string()),
+        }
+    }
+}
+
+fn load_certs(path: &PathBuf) -> Result<Vec<rustls::pki_types::CertificateDer<'static>>> {
+    rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)).collect()
+}
+
+fn load_key(path: &PathBuf) -> Result<rustls::pki_types::PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("no private key in {path:?}")))
+}
+
+fn connector(tls: &TlsConfig) -> Result<TlsConnector> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(cert_path) = &tls.tls_cert {
+        for cert in load_certs(cert_path)? {
+            roots
+                .add(cert)
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        }
+    }
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn acceptor(tls: &TlsConfig) -> Result<TlsAcceptor> {
+    let cert_path = tls
+        .tls_cert
+        .as_ref()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "--tls requires --tls-cert"))?;
+    let key_path = tls
+        .tls_key
+        .as_ref()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "--tls requires --tls-key"))?;
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn ws_err(err: tokio_tungstenite::tungstenite::Error) -> Error {
+    Error::other(err)
+}
+
+/// Adapts a `WebSocketStream` into a plain `AsyncRead + AsyncWrite`, so the
+/// rest of the tunnel code never has to know it's talking over WebSocket
+/// frames instead of a raw byte stream. Each `flush()` sends exactly one
+/// binary message containing everything written since the last flush, which
+/// is what lets callers map one UoT `[Address][len][payload]` frame to one
+/// WebSocket message: write the frame's bytes, then flush.
+struct WsStream<S> {
+    inner: tokio_tungstenite::WebSocketStream<S>,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+}
+
+impl<S> WsStream<S> {
+    fn new(inner: tokio_tungstenite::WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        loop {
+            if !self.read_buf.is_empty() {
+                let n = buf.remaining().min(self.read_buf.len());
+                buf.put_slice(&self.read_buf[..n]);
+                self.read_buf.drain(..n);
+                return Poll::Ready(Ok(()));
+            }
+            match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(Message::Binary(data)))) => {
+                    self.read_buf = data;
+                }
+                Poll::Ready(Some(Ok(_))) => continue,
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(ws_err(err))),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        self.write_buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if !self.write_buf.is_empty() {
+            match Pin::new(&mut self.inner).poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(ws_err(err))),
+                Poll::Pending => return Poll::Pending,
+            }
+            let frame = std::mem::take(&mut self.write_buf);
+            Pin::new(&mut self.inner)
+                .start_send(Message::Binary(frame))
+                .map_err(ws_err)?;
+        }
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(ws_err)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx).map_err(ws_err)
+    }
+}
+
+/// Upgrades an already-established stream to a WebSocket connection via an
+/// HTTP Upgrade handshake to `path` on `host`. Used both by [`connect`] for
+/// endpoints dialed directly over WebSocket, and to WS-wrap a stream
+/// tunneled through an intermediate proxy, mirroring [`tls_connect`].
+pub async fn ws_connect(stream: BoxedStream, host: &str, path: &str) -> Result<BoxedStream> {
+    let request = http::Request::builder()
+        .uri(format!("ws://{host}{path}"))
+        .header("Host", host)
+        .body(())
+        .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    let (ws_stream, _response) = tokio_tungstenite::client_async(request, stream)
+        .await
+        .map_err(ws_err)?;
+    Ok(Box::new(WsStream::new(ws_stream)))
+}
+
+async fn ws_accept(stream: BoxedStream) -> Result<BoxedStream> {
+    let ws_stream = tokio_tungstenite::accept_async(stream).await.map_err(ws_err)?;
+    Ok(Box::new(WsStream::new(ws_stream)))
+}
+
+/// Connects to `addr`, transparently dispatching to TCP or a Unix domain
+/// socket (`unix:/path/to/socket`), optionally negotiating TLS, and then
+/// optionally upgrading to a WebSocket connection — in that order, so `--ws`
+/// and `--tls` can be combined.
+pub async fn connect(addr: &str, tls: Option<&TlsConfig>, ws: Option<&WsConfig>) -> Result<BoxedStream> {
+    let stream: BoxedStream = match Endpoint::parse(addr) {
+        Endpoint::Tcp(addr) => Box::new(TcpStream::connect(&addr).await?),
+        Endpoint::Unix(path) => Box::new(UnixStream::connect(path).await?),
+    };
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let stream = match tls {
+        Some(tls) if tls.tls => tls_connect(stream, host, tls).await?,
+        _ => stream,
+    };
+    match ws {
+        Some(ws) if ws.ws => ws_connect(stream, host, &ws.ws_path).await,
+        _ => Ok(stream),
+    }
+}
+
+/// Upgrades an already-established stream to TLS, using `sni_host` as the
+/// server name to verify. Used both by [`connect`] for endpoints dialed
+/// directly over TLS, and to TLS-wrap a stream tunneled through an
+/// intermediate proxy, where the proxy only relays bytes and never
+/// terminates TLS itself.
+pub async fn tls_connect(stream: BoxedStream, sni_host: &str, tls: &TlsConfig) -> Result<BoxedStream> {
+    let domain = ServerName::try_from(sni_host.to_string())
+        .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    Ok(Box::new(connector(tls)?.connect(domain, stream).await?))
+}
+
+/// Listens on `addr`, transparently dispatching to TCP or a Unix domain
+/// socket (`unix:/path/to/socket`). When `tls`/`ws` request it, every
+/// accepted connection performs a TLS handshake and/or a WebSocket upgrade
+/// handshake before being handed back.
+pub enum Listener {
+    Tcp(TcpListener, Option<TlsAcceptor>, bool),
+    Unix(UnixListener, Option<TlsAcceptor>, bool),
+}
+
+impl Listener {
+    pub async fn bind(addr: &str, tls: Option<&TlsConfig>, ws: Option<&WsConfig>) -> Result<Self> {
+        let tls_acceptor = match tls {
+            Some(tls) if tls.tls => Some(acceptor(tls)?),
+            _ => None,
+        };
+        let ws_enabled = matches!(ws, Some(ws) if ws.ws);
+        match Endpoint::parse(addr) {
+            Endpoint::Tcp(addr) => Ok(Self::Tcp(
+                TcpListener::bind(addr).await?,
+                tls_acceptor,
+                ws_enabled,
+            )),
+            Endpoint::Unix(path) => {
+                let _ = std::fs::remove_file(&path);
+                Ok(Self::Unix(
+                    UnixListener::bind(path)?,
+                    tls_acceptor,
+                    ws_enabled,
+                ))
+            }
+        }
+    }
+
+    pub async fn accept(&self) -> Result<(BoxedStream, String)> {
+        let (stream, peer, tls_acceptor, ws_enabled): (BoxedStream, String, &Option<TlsAcceptor>, bool) =
+            match self {
+                Self::Tcp(listener, tls_acceptor, ws_enabled) => {
+                    let (stream, addr) = listener.accept().await?;
+                    (Box::new(stream), addr.to_string(), tls_acceptor, *ws_enabled)
+                }
+                Self::Unix(listener, tls_acceptor, ws_enabled) => {
+                    let (stream, addr) = listener.accept().await?;
+                    (Box::new(stream), format!("{addr:?}"), tls_acceptor, *ws_enabled)
+                }
+            };
+        let stream = match tls_acceptor {
+            Some(acceptor) => Box::new(acceptor.accept(stream).await?) as BoxedStream,
+            None => stream,
+        };
+        let stream = if ws_enabled { ws_accept(stream).await? } else { stream };
+        Ok((stream, peer))
+    }
+}